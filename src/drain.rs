@@ -0,0 +1,626 @@
+//! # Drains
+//!
+//! A `Drain` is the sink at the end of a `Logger` hierarchy: it is
+//! responsible for formatting a record and writing it somewhere (a file,
+//! stderr, a socket, ...).
+//!
+//! Rather than receiving a whole record at once, a `Drain` opens a
+//! `RecordDrain` via `begin` and has fields streamed into it one at a
+//! time through `add`; the record is considered closed once the
+//! `RecordDrain` is dropped. This lets a structured sink (see `json`)
+//! write each field as it arrives instead of buffering the whole set.
+
+use std::io;
+use std::io::Write;
+use std::thread;
+use std::thread::JoinHandle;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::VecDeque;
+
+use crossbeam_channel::{self, Sender};
+use chrono;
+
+use {Level, Value};
+use logger::RecordInfo;
+
+/// A sink for log records
+pub trait Drain: Send + Sync {
+    /// Open a record for `info`
+    ///
+    /// The returned `RecordDrain` receives every key-value attached to
+    /// the logger as well as the ones passed to this particular log
+    /// call, in order, through `add`; it is closed, and should flush
+    /// whatever it has buffered, when dropped.
+    fn begin<'a>(&'a self, info: &mut RecordInfo) -> Box<RecordDrain + 'a>;
+}
+
+/// A single log record being streamed into a `Drain`
+pub trait RecordDrain {
+    /// Add one key-value field to the record
+    fn add(&mut self, key: &'static str, value: &Value);
+}
+
+// A `RecordDrain` that discards every field; shared by drains that want
+// to produce no output for a record at all.
+struct NullRecord;
+
+impl RecordDrain for NullRecord {
+    fn add(&mut self, _key: &'static str, _value: &Value) {}
+}
+
+/// A `Drain` that discards all records
+pub struct Discard;
+
+impl Drain for Discard {
+    fn begin<'a>(&'a self, _info: &mut RecordInfo) -> Box<RecordDrain + 'a> {
+        Box::new(NullRecord)
+    }
+}
+
+/// Create a `Drain` that discards all records
+pub fn discard() -> Discard {
+    Discard
+}
+
+/// A `Drain` that suppresses records below a minimum `Level`
+///
+/// Created with `level_filter`.
+pub struct LevelFilter<D: Drain> {
+    min: Level,
+    drain: D,
+}
+
+impl<D: Drain> Drain for LevelFilter<D> {
+    fn begin<'a>(&'a self, info: &mut RecordInfo) -> Box<RecordDrain + 'a> {
+        if info.level() < self.min {
+            Box::new(NullRecord)
+        } else {
+            self.drain.begin(info)
+        }
+    }
+}
+
+/// Wrap `drain` so records below `min` are dropped without reaching it
+pub fn level_filter<D: Drain>(min: Level, drain: D) -> LevelFilter<D> {
+    LevelFilter {
+        min: min,
+        drain: drain,
+    }
+}
+
+// The parts of a `RecordInfo` a `RecordDrain` needs to keep around past
+// the `begin` call, so it can hand a `RecordInfo` to `Value::serialize`
+// from inside `add`, without borrowing the original (which does not
+// outlive `begin`).
+#[derive(Clone)]
+struct CapturedInfo {
+    level: Level,
+    ts: chrono::DateTime<chrono::UTC>,
+    msg: String,
+}
+
+impl CapturedInfo {
+    fn new(info: &mut RecordInfo) -> CapturedInfo {
+        CapturedInfo {
+            level: info.level(),
+            ts: info.ts(),
+            msg: info.msg().to_owned(),
+        }
+    }
+
+    fn record_info(&self) -> RecordInfo {
+        let info = RecordInfo::new(self.level, &self.msg);
+        info.set_ts(self.ts);
+        info
+    }
+}
+
+/// What to do with a record when `Async`'s queue is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Block the calling thread until there is room in the queue
+    Block,
+    /// Drop the record and count it in `Async::dropped`
+    DropAndCount,
+}
+
+// A record rendered into an owned form so it can cross the channel to the
+// worker thread without borrowing anything from the caller.
+struct OwnedRecord {
+    level: Level,
+    ts: chrono::DateTime<chrono::UTC>,
+    msg: String,
+    kvs: Vec<(&'static str, String)>,
+}
+
+/// A `Drain` that offloads formatting and I/O for an inner `Drain` to a
+/// dedicated background thread
+///
+/// Fields are rendered to owned strings on the calling thread as they
+/// arrive and handed, once the record closes, to a bounded channel; the
+/// worker thread pops records and feeds them to the inner drain one at a
+/// time. This keeps a slow inner drain (disk, network, ...) off of
+/// application threads.
+pub struct Async {
+    tx: Option<Sender<OwnedRecord>>,
+    policy: OverflowPolicy,
+    dropped: AtomicUsize,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Async {
+    /// Wrap `drain` in a background worker thread
+    ///
+    /// `capacity` bounds the channel between callers and the worker;
+    /// once full, `policy` decides whether closing a record blocks or
+    /// drops it.
+    pub fn new<D: Drain + 'static>(drain: D, capacity: usize, policy: OverflowPolicy) -> Async {
+        let (tx, rx) = crossbeam_channel::bounded::<OwnedRecord>(capacity);
+
+        let worker = thread::spawn(move || {
+            for rec in rx {
+                let mut info = RecordInfo::new(rec.level, &rec.msg);
+                info.set_ts(rec.ts);
+                let mut record = drain.begin(&mut info);
+                for (k, v) in rec.kvs {
+                    record.add(k, &v);
+                }
+            }
+        });
+
+        Async {
+            tx: Some(tx),
+            policy: policy,
+            dropped: AtomicUsize::new(0),
+            worker: Some(worker),
+        }
+    }
+
+    /// Number of records dropped so far under `OverflowPolicy::DropAndCount`
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, record: OwnedRecord) {
+        let tx = self.tx.as_ref().expect("Async: sender gone before drop");
+        match self.policy {
+            OverflowPolicy::Block => {
+                let _ = tx.send(record);
+            }
+            OverflowPolicy::DropAndCount => {
+                if tx.try_send(record).is_err() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+struct AsyncRecord<'a> {
+    async_drain: &'a Async,
+    captured: CapturedInfo,
+    kvs: Vec<(&'static str, String)>,
+}
+
+impl<'a> RecordDrain for AsyncRecord<'a> {
+    fn add(&mut self, key: &'static str, value: &Value) {
+        let info = self.captured.record_info();
+        let mut buf = Vec::new();
+        let _ = value.serialize(&info, key, &mut buf);
+        self.kvs.push((key, String::from_utf8_lossy(&buf).into_owned()));
+    }
+}
+
+impl<'a> Drop for AsyncRecord<'a> {
+    fn drop(&mut self) {
+        let kvs = ::std::mem::take(&mut self.kvs);
+        self.async_drain.send(OwnedRecord {
+            level: self.captured.level,
+            ts: self.captured.ts,
+            msg: self.captured.msg.clone(),
+            kvs: kvs,
+        });
+    }
+}
+
+impl Drain for Async {
+    fn begin<'a>(&'a self, info: &mut RecordInfo) -> Box<RecordDrain + 'a> {
+        Box::new(AsyncRecord {
+            async_drain: self,
+            captured: CapturedInfo::new(info),
+            kvs: Vec::new(),
+        })
+    }
+}
+
+impl Drop for Async {
+    fn drop(&mut self) {
+        // Dropping the sender closes the channel, so the worker's receive
+        // loop finishes (after draining whatever is still queued) instead
+        // of blocking forever; only then can we join it.
+        self.tx = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Formatted records kept by a `RingBuffer`, oldest first, along with their
+// combined length so eviction doesn't have to re-sum on every call.
+struct RingState {
+    records: VecDeque<Vec<u8>>,
+    len: usize,
+}
+
+/// A `Drain` that keeps the last `capacity` bytes or so of formatted
+/// records in memory, evicting whole oldest records as new ones arrive
+///
+/// Useful for cheaply retaining recent trace/debug output and only
+/// extracting it when something more severe happens; pair it with
+/// `duplicate` to also stream to a normal drain.
+pub struct RingBuffer {
+    capacity: usize,
+    state: Mutex<RingState>,
+}
+
+impl RingBuffer {
+    /// Create a `RingBuffer` that keeps at most about `capacity` bytes
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer {
+            capacity: capacity,
+            state: Mutex::new(RingState {
+                records: VecDeque::new(),
+                len: 0,
+            }),
+        }
+    }
+
+    /// Hand the current contents, oldest to newest, to `f`
+    pub fn extract<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> R {
+        let state = self.state.lock().unwrap();
+        let mut flat = Vec::with_capacity(state.len);
+        for record in &state.records {
+            flat.extend_from_slice(record);
+        }
+        f(&flat)
+    }
+
+    /// Drop everything currently buffered
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.records.clear();
+        state.len = 0;
+    }
+
+    fn push(&self, record: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.len += record.len();
+        state.records.push_back(record);
+        while state.len > self.capacity && state.records.len() > 1 {
+            if let Some(oldest) = state.records.pop_front() {
+                state.len -= oldest.len();
+            }
+        }
+    }
+}
+
+impl Drain for RingBuffer {
+    fn begin<'a>(&'a self, info: &mut RecordInfo) -> Box<RecordDrain + 'a> {
+        Box::new(RingBufferRecord {
+            ring: self,
+            captured: CapturedInfo::new(info),
+            buf: Vec::new(),
+            header_written: false,
+        })
+    }
+}
+
+struct RingBufferRecord<'a> {
+    ring: &'a RingBuffer,
+    captured: CapturedInfo,
+    buf: Vec<u8>,
+    header_written: bool,
+}
+
+impl<'a> RingBufferRecord<'a> {
+    fn ensure_header(&mut self) {
+        if !self.header_written {
+            let _ = write!(self.buf,
+                            "{} {:?} {}",
+                            self.captured.ts,
+                            self.captured.level,
+                            self.captured.msg);
+            self.header_written = true;
+        }
+    }
+}
+
+impl<'a> RecordDrain for RingBufferRecord<'a> {
+    fn add(&mut self, key: &'static str, value: &Value) {
+        self.ensure_header();
+        let info = self.captured.record_info();
+        let _ = write!(self.buf, " {}=", key);
+        let _ = value.serialize(&info, key, &mut self.buf);
+    }
+}
+
+impl<'a> Drop for RingBufferRecord<'a> {
+    fn drop(&mut self) {
+        self.ensure_header();
+        self.buf.push(b'\n');
+        let record = ::std::mem::take(&mut self.buf);
+        self.ring.push(record);
+    }
+}
+
+/// A `Drain` that feeds every record to two inner drains
+///
+/// Created with `duplicate`.
+pub struct Duplicate<D1: Drain, D2: Drain> {
+    d1: D1,
+    d2: D2,
+}
+
+impl<D1: Drain, D2: Drain> Drain for Duplicate<D1, D2> {
+    fn begin<'a>(&'a self, info: &mut RecordInfo) -> Box<RecordDrain + 'a> {
+        Box::new(DuplicateRecord {
+            r1: self.d1.begin(info),
+            r2: self.d2.begin(info),
+        })
+    }
+}
+
+struct DuplicateRecord<'a> {
+    r1: Box<RecordDrain + 'a>,
+    r2: Box<RecordDrain + 'a>,
+}
+
+impl<'a> RecordDrain for DuplicateRecord<'a> {
+    fn add(&mut self, key: &'static str, value: &Value) {
+        self.r1.add(key, value);
+        self.r2.add(key, value);
+    }
+}
+
+/// Feed every record to both `d1` and `d2`
+pub fn duplicate<D1: Drain, D2: Drain>(d1: D1, d2: D2) -> Duplicate<D1, D2> {
+    Duplicate {
+        d1: d1,
+        d2: d2,
+    }
+}
+
+// Write `s` as a properly escaped, quoted JSON string, so that arbitrary
+// message text or field values (a `"`, a `\`, a newline, ...) can't break
+// out of the surrounding JSON object.
+fn write_json_str(io: &mut io::Write, s: &str) -> io::Result<()> {
+    try!(io.write_all(b"\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(io.write_all(b"\\\"")),
+            '\\' => try!(io.write_all(b"\\\\")),
+            '\n' => try!(io.write_all(b"\\n")),
+            '\r' => try!(io.write_all(b"\\r")),
+            '\t' => try!(io.write_all(b"\\t")),
+            c if (c as u32) < 0x20 => try!(write!(io, "\\u{:04x}", c as u32)),
+            c => try!(write!(io, "{}", c)),
+        }
+    }
+    io.write_all(b"\"")
+}
+
+/// A `Drain` that writes each record as a JSON object, one per line
+///
+/// Created with `json`. Fields are written to the underlying `io::Write`
+/// as they arrive through `add`, rather than being buffered and
+/// formatted all at once.
+pub struct Json<W: io::Write + Send + Sync> {
+    io: Mutex<W>,
+}
+
+impl<W: io::Write + Send + Sync> Json<W> {
+    /// Write records as JSON objects to `io`, one per line
+    pub fn new(io: W) -> Json<W> {
+        Json { io: Mutex::new(io) }
+    }
+}
+
+/// Write records as JSON objects to `io`, one per line
+pub fn json<W: io::Write + Send + Sync>(io: W) -> Json<W> {
+    Json::new(io)
+}
+
+impl<W: io::Write + Send + Sync> Drain for Json<W> {
+    fn begin<'a>(&'a self, info: &mut RecordInfo) -> Box<RecordDrain + 'a> {
+        let captured = CapturedInfo::new(info);
+        let mut io = self.io.lock().unwrap();
+        let _ = write!(io, "{{\"ts\":\"{}\",\"level\":\"{:?}\",\"msg\":", captured.ts, captured.level);
+        let _ = write_json_str(&mut *io, &captured.msg);
+        Box::new(JsonRecord {
+            io: io,
+            captured: captured,
+        })
+    }
+}
+
+struct JsonRecord<'a, W: io::Write + Send + Sync + 'a> {
+    io: ::std::sync::MutexGuard<'a, W>,
+    captured: CapturedInfo,
+}
+
+impl<'a, W: io::Write + Send + Sync> RecordDrain for JsonRecord<'a, W> {
+    fn add(&mut self, key: &'static str, value: &Value) {
+        let info = self.captured.record_info();
+        let mut buf = Vec::new();
+        let _ = value.serialize(&info, key, &mut buf);
+        let _ = write!(self.io, ",");
+        let _ = write_json_str(&mut *self.io, key);
+        let _ = write!(self.io, ":");
+        let _ = write_json_str(&mut *self.io, &String::from_utf8_lossy(&buf));
+    }
+}
+
+impl<'a, W: io::Write + Send + Sync> Drop for JsonRecord<'a, W> {
+    fn drop(&mut self) {
+        let _ = writeln!(self.io, "}}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // A `Drain` that just remembers every record it sees, so tests can
+    // check what actually reached an inner drain.
+    struct CollectDrain(Arc<Mutex<Vec<String>>>);
+
+    struct CollectRecord {
+        out: Arc<Mutex<Vec<String>>>,
+        msg: String,
+        kvs: Vec<String>,
+    }
+
+    impl Drain for CollectDrain {
+        fn begin<'a>(&'a self, info: &mut RecordInfo) -> Box<RecordDrain + 'a> {
+            Box::new(CollectRecord {
+                out: self.0.clone(),
+                msg: info.msg().to_owned(),
+                kvs: Vec::new(),
+            })
+        }
+    }
+
+    impl RecordDrain for CollectRecord {
+        fn add(&mut self, key: &'static str, value: &Value) {
+            let info = RecordInfo::new(Level::Info, &self.msg);
+            let mut buf = Vec::new();
+            let _ = value.serialize(&info, key, &mut buf);
+            self.kvs.push(format!("{}={}", key, String::from_utf8_lossy(&buf)));
+        }
+    }
+
+    impl Drop for CollectRecord {
+        fn drop(&mut self) {
+            self.out.lock().unwrap().push(format!("{} {}", self.msg, self.kvs.join(" ")));
+        }
+    }
+
+    #[test]
+    fn async_forwards_records_to_inner_drain() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let async_drain = Async::new(CollectDrain(out.clone()), 4, OverflowPolicy::Block);
+        {
+            let mut info = RecordInfo::new(Level::Info, "hello");
+            let mut record = async_drain.begin(&mut info);
+            record.add("key", &"value");
+        }
+        // Dropping closes the channel and joins the worker, so every
+        // queued record is guaranteed to have reached `CollectDrain` by
+        // the time this returns.
+        drop(async_drain);
+
+        let out = out.lock().unwrap();
+        assert_eq!(&out[..], &["hello key=value".to_owned()]);
+    }
+
+    #[test]
+    fn level_filter_drops_below_min_and_passes_at_or_above() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let filtered = level_filter(Level::Warning, CollectDrain(out.clone()));
+
+        {
+            let mut info = RecordInfo::new(Level::Info, "too quiet");
+            let mut record = filtered.begin(&mut info);
+            record.add("key", &"value");
+        }
+        assert!(out.lock().unwrap().is_empty(), "below min should never reach the inner drain");
+
+        {
+            let mut info = RecordInfo::new(Level::Warning, "loud enough");
+            let mut record = filtered.begin(&mut info);
+            record.add("key", &"value");
+        }
+        assert_eq!(&out.lock().unwrap()[..], &["loud enough key=value".to_owned()]);
+    }
+
+    fn push_record(ring: &RingBuffer, level: Level, msg: &'static str, kvs: &[(&'static str, &'static str)]) {
+        let mut info = RecordInfo::new(level, msg);
+        let mut record = ring.begin(&mut info);
+        for &(k, v) in kvs {
+            record.add(k, &v);
+        }
+    }
+
+    #[test]
+    fn ring_buffer_extract_is_oldest_to_newest_and_header_has_the_level() {
+        let ring = RingBuffer::new(4096);
+        push_record(&ring, Level::Info, "first", &[("k", "1")]);
+        push_record(&ring, Level::Critical, "second", &[("k", "2")]);
+
+        ring.extract(|buf| {
+            let text = String::from_utf8_lossy(buf);
+            let first_at = text.find("first").expect("first record missing");
+            let second_at = text.find("second").expect("second record missing");
+            assert!(first_at < second_at, "records must come out oldest-first");
+            assert!(text.contains("Critical"), "header should carry the level");
+        });
+    }
+
+    #[test]
+    fn ring_buffer_evicts_oldest_once_over_capacity() {
+        // Small enough that the first record alone already fills it, so
+        // pushing a second must evict the first.
+        let ring = RingBuffer::new(1);
+        push_record(&ring, Level::Info, "first", &[]);
+        push_record(&ring, Level::Info, "second", &[]);
+
+        ring.extract(|buf| {
+            let text = String::from_utf8_lossy(buf);
+            assert!(!text.contains("first"), "oldest record should have been evicted");
+            assert!(text.contains("second"));
+        });
+    }
+
+    #[test]
+    fn ring_buffer_clear_empties_the_buffer() {
+        let ring = RingBuffer::new(4096);
+        push_record(&ring, Level::Info, "first", &[]);
+        ring.clear();
+
+        ring.extract(|buf| assert!(buf.is_empty()));
+    }
+
+    #[test]
+    fn duplicate_feeds_both_inner_drains() {
+        let out1 = Arc::new(Mutex::new(Vec::new()));
+        let out2 = Arc::new(Mutex::new(Vec::new()));
+        let dup = duplicate(CollectDrain(out1.clone()), CollectDrain(out2.clone()));
+
+        {
+            let mut info = RecordInfo::new(Level::Info, "dup");
+            let mut record = dup.begin(&mut info);
+            record.add("k", &"v");
+        }
+
+        assert_eq!(&out1.lock().unwrap()[..], &["dup k=v".to_owned()]);
+        assert_eq!(&out2.lock().unwrap()[..], &["dup k=v".to_owned()]);
+    }
+
+    #[test]
+    fn json_escapes_quotes_backslashes_and_control_chars() {
+        let mut out: Vec<u8> = Vec::new();
+        {
+            let json_drain = Json::new(&mut out);
+            let mut info = RecordInfo::new(Level::Info, "msg with \"quotes\"");
+            let mut record = json_drain.begin(&mut info);
+            record.add("val", &"has \"quotes\" and \\backslash\\ and\nnewline");
+        }
+        let text = String::from_utf8(out).unwrap();
+
+        assert!(text.contains("\"msg\":\"msg with \\\"quotes\\\"\""));
+        assert!(text.contains("\"val\":\"has \\\"quotes\\\" and \\\\backslash\\\\ and\\nnewline\""));
+        assert!(text.ends_with("}\n"));
+    }
+}
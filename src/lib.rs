@@ -0,0 +1,191 @@
+//! # term
+//!
+//! A structured, composable logging library.
+//!
+//! Loggers are built from `Logger::new_root`, can have key-values
+//! attached, and form hierarchies of children sharing a common
+//! drain. See `Logger` for details.
+
+#![warn(missing_docs)]
+
+extern crate crossbeam;
+extern crate crossbeam_channel;
+extern crate chrono;
+
+pub mod drain;
+mod logger;
+mod value;
+
+pub use logger::{Logger, RecordInfo, OwnedKeyValueNode};
+pub use value::Value;
+
+/// Logging level
+///
+/// Levels are ordered by severity: `Off` is more severe than `Critical`,
+/// and `Trace` is the least severe. `Off` never appears on an actual
+/// record; it exists only to be used as a filtering threshold that
+/// suppresses everything.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Level {
+    /// Critical
+    Critical,
+    /// Error
+    Error,
+    /// Warning
+    Warning,
+    /// Info
+    Info,
+    /// Debug
+    Debug,
+    /// Trace
+    Trace,
+    /// Suppress all levels
+    Off,
+}
+
+impl Level {
+    /// Severity rank of this level, `Off` highest, `Trace` lowest
+    pub fn as_usize(&self) -> usize {
+        match *self {
+            Level::Off => 6,
+            Level::Critical => 5,
+            Level::Error => 4,
+            Level::Warning => 3,
+            Level::Info => 2,
+            Level::Debug => 1,
+            Level::Trace => 0,
+        }
+    }
+}
+
+impl PartialOrd for Level {
+    fn partial_cmp(&self, other: &Level) -> Option<::std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Level {
+    fn cmp(&self, other: &Level) -> ::std::cmp::Ordering {
+        self.as_usize().cmp(&other.as_usize())
+    }
+}
+
+// The compiled-in ceiling on what levels are enabled at all: code gated by
+// `critical!`/.../`trace!` below this level compiles down to nothing, not
+// even `RecordInfo` construction. At most one `max_level_*` feature (or,
+// in a release build, `release_max_level_*`) should be set; `Trace`
+// (everything enabled) is the default. Listed most-restrictive first so
+// each `cfg` only has to rule out the ones above it.
+macro_rules! static_max_level {
+    (@rest [$($above:expr),*] ($feature:expr, $variant:ident) $($rest:tt)*) => {
+        #[cfg(all(feature = $feature, not(any($(feature = $above),*))))]
+        #[doc(hidden)]
+        pub const STATIC_MAX_LEVEL: Level = Level::$variant;
+        static_max_level!(@rest [$($above,)* $feature] $($rest)*);
+    };
+    (@rest [$($above:expr),*]) => {
+        #[cfg(not(any($(feature = $above),*)))]
+        #[doc(hidden)]
+        pub const STATIC_MAX_LEVEL: Level = Level::Trace;
+    };
+    ($(($feature:expr, $variant:ident)),*) => {
+        static_max_level!(@rest [] $(($feature, $variant))*);
+    };
+}
+
+#[cfg(debug_assertions)]
+static_max_level!(("max_level_off", Off),
+                   ("max_level_critical", Critical),
+                   ("max_level_error", Error),
+                   ("max_level_warning", Warning),
+                   ("max_level_info", Info),
+                   ("max_level_debug", Debug),
+                   ("max_level_trace", Trace));
+
+#[cfg(not(debug_assertions))]
+static_max_level!(("release_max_level_off", Off),
+                   ("release_max_level_critical", Critical),
+                   ("release_max_level_error", Error),
+                   ("release_max_level_warning", Warning),
+                   ("release_max_level_info", Info),
+                   ("release_max_level_debug", Debug),
+                   ("release_max_level_trace", Trace),
+                   ("max_level_off", Off),
+                   ("max_level_critical", Critical),
+                   ("max_level_error", Error),
+                   ("max_level_warning", Warning),
+                   ("max_level_info", Info),
+                   ("max_level_debug", Debug),
+                   ("max_level_trace", Trace));
+
+/// A key-value pair owned by a `Logger`
+///
+/// The value is boxed behind `Value`, so it can be a plain value (a string,
+/// a `char`, a `bool` or a primitive number) or a `Fn(&RecordInfo) -> T`
+/// closure evaluated lazily at log time.
+pub type OwnedKeyValue = (&'static str, Box<Value>);
+
+/// A key-value pair borrowed for the duration of a single log call
+pub type BorrowedKeyValue<'a> = (&'static str, &'a Value);
+
+/// Build a list of `OwnedKeyValue`
+///
+/// Used with `Logger::new_root` and `Logger::new`. Values can be anything
+/// implementing `Value`, including a closure taking `&RecordInfo` for
+/// context that should be computed fresh on every log call:
+///
+/// ```
+/// #[macro_use]
+/// extern crate term;
+///
+/// fn main() {
+///     let root = term::Logger::new_root(o!("key1" => "value1", "key2" => "value2"));
+/// }
+/// ```
+#[macro_export]
+macro_rules! o {
+    ($($k:expr => $v:expr),* $(,)*) => {
+        vec![$(($k, Box::new($v) as Box<$crate::Value>)),*]
+    };
+}
+
+/// Build a list of `BorrowedKeyValue`
+///
+/// Used with logging calls like `Logger::info`.
+#[macro_export]
+macro_rules! b {
+    ($($k:expr => $v:expr),* $(,)*) => {
+        &[$(($k, &$v as &$crate::Value)),*]
+    };
+}
+
+// Each of these mirrors a `Logger` method of the same name, but first
+// checks `STATIC_MAX_LEVEL` so that a level disabled by a `max_level_*`
+// feature compiles away entirely at the call site: no `RecordInfo`, no
+// drain call, nothing.
+macro_rules! level_macro {
+    ($name:ident, $doc:expr, $variant:ident) => {
+        #[doc = $doc]
+        #[macro_export]
+        macro_rules! $name {
+            ($l:expr, $msg:expr) => {
+                $name!($l, $msg, &[])
+            };
+            ($l:expr, $msg:expr, $values:expr) => {
+                if $crate::Level::$variant.as_usize() >= $crate::STATIC_MAX_LEVEL.as_usize() {
+                    $l.$name($msg, $values)
+                }
+            };
+        }
+    }
+}
+
+level_macro!(critical, "Log a critical level record, unless disabled by a `max_level_*` feature",
+             Critical);
+level_macro!(error, "Log an error level record, unless disabled by a `max_level_*` feature",
+             Error);
+level_macro!(warn, "Log a warning level record, unless disabled by a `max_level_*` feature",
+             Warning);
+level_macro!(info, "Log an info level record, unless disabled by a `max_level_*` feature", Info);
+level_macro!(debug, "Log a debug level record, unless disabled by a `max_level_*` feature", Debug);
+level_macro!(trace, "Log a trace level record, unless disabled by a `max_level_*` feature", Trace);
@@ -5,8 +5,10 @@
 //!
 //! Each logger is built with a set of key-values.
 //!
-//! Child loggers are build from existing loggers, and copy
-//! all the key-values from their parents
+//! Child loggers are built from existing loggers by chaining onto an
+//! immutable, `Arc`-shared list of key-values (see `OwnedKeyValueNode`),
+//! so creating a child is a single small allocation no matter how deep
+//! the hierarchy already is.
 //!
 //! Loggers form hierarchies sharing a drain. Setting a drain on
 //! any logger will change it on all loggers in given hierarchy.
@@ -19,8 +21,76 @@ use drain;
 
 use chrono;
 
-thread_local! {
-    static TL_BUF: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(128))
+/// One link in the immutable chain of key-values attached to a `Logger`
+///
+/// `Logger::new` allocates one of these per child, with `parent` pointing
+/// at the node of the logger it was built from, so a child never copies
+/// its ancestors' values. Use `iter` to walk the whole chain.
+pub struct OwnedKeyValueNode {
+    parent: Option<Arc<OwnedKeyValueNode>>,
+    values: Vec<OwnedKeyValue>,
+}
+
+impl OwnedKeyValueNode {
+    /// Build a root node with no parent
+    pub fn new_root(values: Vec<OwnedKeyValue>) -> Self {
+        OwnedKeyValueNode {
+            parent: None,
+            values: values,
+        }
+    }
+
+    /// Build a node chained onto `parent`
+    pub fn new_child(parent: Arc<OwnedKeyValueNode>, values: Vec<OwnedKeyValue>) -> Self {
+        OwnedKeyValueNode {
+            parent: Some(parent),
+            values: values,
+        }
+    }
+
+    /// Iterate over every key-value in this chain, root-first
+    ///
+    /// Drains receive key-values in this order, so that values set on a
+    /// root logger are formatted before values set on its children.
+    pub fn iter(&self) -> OwnedKeyValueIter {
+        let mut nodes = Vec::new();
+        let mut cur = Some(self);
+        while let Some(node) = cur {
+            nodes.push(node);
+            cur = node.parent.as_deref();
+        }
+        nodes.reverse();
+        OwnedKeyValueIter {
+            nodes: nodes,
+            node_idx: 0,
+            val_idx: 0,
+        }
+    }
+}
+
+/// Iterator over the key-values of an `OwnedKeyValueNode` chain, root-to-leaf
+pub struct OwnedKeyValueIter<'a> {
+    nodes: Vec<&'a OwnedKeyValueNode>,
+    node_idx: usize,
+    val_idx: usize,
+}
+
+impl<'a> Iterator for OwnedKeyValueIter<'a> {
+    type Item = &'a OwnedKeyValue;
+
+    fn next(&mut self) -> Option<&'a OwnedKeyValue> {
+        while self.node_idx < self.nodes.len() {
+            let node = self.nodes[self.node_idx];
+            if self.val_idx < node.values.len() {
+                let v = &node.values[self.val_idx];
+                self.val_idx += 1;
+                return Some(v);
+            }
+            self.node_idx += 1;
+            self.val_idx = 0;
+        }
+        None
+    }
 }
 
 // TODO: Implement custom clone, that starts with a new buffer
@@ -28,7 +98,7 @@ thread_local! {
 /// Logger
 pub struct Logger {
     drain: Arc<ArcCell<Box<drain::Drain>>>,
-    values: Vec<OwnedKeyValue>,
+    values: Arc<OwnedKeyValueNode>,
 }
 
 impl Logger {
@@ -41,23 +111,26 @@ impl Logger {
     ///
     /// ```
     /// #[macro_use]
-    /// extern crate slog;
+    /// extern crate term;
     ///
     /// fn main() {
-    ///     let root = slog::Logger::new_root(o!("key1" => "value1", "key2" => "value2"));
+    ///     let root = term::Logger::new_root(o!("key1" => "value1", "key2" => "value2"));
     /// }
-    pub fn new_root(values: &[OwnedKeyValue]) -> Logger {
+    /// ```
+    pub fn new_root(values: Vec<OwnedKeyValue>) -> Logger {
         let drain =
             Arc::new(ArcCell::new(Arc::new(Box::new(drain::discard()) as Box<drain::Drain>)));
         Logger {
             drain: drain,
-            values: values.to_vec(),
+            values: Arc::new(OwnedKeyValueNode::new_root(values)),
         }
     }
 
     /// Build a child logger
     ///
-    /// Child logger copies all existing values from the parent.
+    /// The child links onto the parent's key-value chain instead of
+    /// copying it, so building a child is a single small allocation
+    /// regardless of how many ancestors it has.
     ///
     /// All children, their children and so on, form one hierarchy sharing
     /// a common drain.
@@ -66,19 +139,17 @@ impl Logger {
     ///
     /// ```
     /// #[macro_use]
-    /// extern crate slog;
+    /// extern crate term;
     ///
     /// fn main() {
-    ///     let root = slog::Logger::new_root(o!("key1" => "value1", "key2" => "value2"));
+    ///     let root = term::Logger::new_root(o!("key1" => "value1", "key2" => "value2"));
     ///     let log = root.new(o!("key" => "value"));
     /// }
-
-    pub fn new(&self, values: &[OwnedKeyValue]) -> Logger {
-        let mut new_values = self.values.clone();
-        new_values.extend_from_slice(values);
+    /// ```
+    pub fn new(&self, values: Vec<OwnedKeyValue>) -> Logger {
         Logger {
             drain: self.drain.clone(),
-            values: new_values,
+            values: Arc::new(OwnedKeyValueNode::new_child(self.values.clone(), values)),
         }
     }
 
@@ -102,13 +173,16 @@ impl Logger {
 
         let mut info = RecordInfo::new(lvl, msg);
 
-        // By default errors in loggers are ignored
-        TL_BUF.with(|buf| {
-            let mut buf = buf.borrow_mut();
-            let _ = self.drain.get().log(&mut *buf, &mut info, self.values.as_slice(), values);
-            // TODO: Double check if this will not zero the old bytes as it costs time
-            buf.clear();
-        });
+        let drain = self.drain.get();
+        let mut record = drain.begin(&mut info);
+        for kv in self.values.iter() {
+            record.add(kv.0, &*kv.1);
+        }
+        for &(k, v) in values {
+            record.add(k, v);
+        }
+        // `record` is dropped here, closing it even if a field above
+        // failed to serialize.
     }
 
     /// Log critical level record
@@ -205,3 +279,26 @@ impl<'a> RecordInfo<'a> {
         self.level
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Value;
+
+    fn keys(node: &OwnedKeyValueNode) -> Vec<&'static str> {
+        node.iter().map(|&(k, _)| k).collect()
+    }
+
+    fn values(pairs: Vec<(&'static str, &'static str)>) -> Vec<OwnedKeyValue> {
+        pairs.into_iter().map(|(k, v)| (k, Box::new(v) as Box<Value>)).collect()
+    }
+
+    #[test]
+    fn iter_visits_root_to_leaf() {
+        let root = OwnedKeyValueNode::new_root(values(vec![("a", "1"), ("b", "2")]));
+        let child = OwnedKeyValueNode::new_child(Arc::new(root), values(vec![("c", "3")]));
+        let grandchild = OwnedKeyValueNode::new_child(Arc::new(child), values(vec![("d", "4")]));
+
+        assert_eq!(keys(&grandchild), &["a", "b", "c", "d"]);
+    }
+}
@@ -0,0 +1,100 @@
+//! # Values
+//!
+//! Key-values are not limited to plain strings: anything that implements
+//! `Value` can be attached to a `Logger` or passed to a log call, and is
+//! only serialized when (and if) a record is actually produced.
+//!
+//! This is what lets a closure be used as a value: it is stored as-is and
+//! only called at log time, with the `RecordInfo` of the record being
+//! logged, so a single shared logger can report live-computed context
+//! (a counter, elapsed time, ...) without ever being rebuilt.
+use std::io;
+use std::fmt;
+
+use logger::RecordInfo;
+
+/// A loggable value
+///
+/// Implemented for `&str`, `String`, `char`, `bool` and the primitive
+/// integer and floating-point types, and for closures
+/// `Fn(&RecordInfo) -> T` (with `T: Display`) so that context can be
+/// computed lazily, at the time a record is actually logged. Other
+/// `Display` types do not implement it.
+pub trait Value: Send + Sync {
+    /// Serialize `self`'s value into `io`, for `key` in the record described by `info`
+    ///
+    /// `key` is passed through so a serializer that needs it (a nested
+    /// structure, say) can use it, but most implementations just write
+    /// their own representation and ignore it.
+    fn serialize(&self, info: &RecordInfo, key: &str, io: &mut io::Write) -> io::Result<()>;
+}
+
+impl<'b> Value for &'b str {
+    fn serialize(&self, _info: &RecordInfo, _key: &str, io: &mut io::Write) -> io::Result<()> {
+        write!(io, "{}", self)
+    }
+}
+
+impl Value for String {
+    fn serialize(&self, _info: &RecordInfo, _key: &str, io: &mut io::Write) -> io::Result<()> {
+        write!(io, "{}", self)
+    }
+}
+
+macro_rules! impl_value_for_display {
+    ($($t:ty),*) => {
+        $(
+            impl Value for $t {
+                fn serialize(&self, _info: &RecordInfo, _key: &str, io: &mut io::Write) -> io::Result<()> {
+                    write!(io, "{}", self)
+                }
+            }
+        )*
+    }
+}
+
+impl_value_for_display!(char, bool,
+                         i8, i16, i32, i64, isize,
+                         u8, u16, u32, u64, usize,
+                         f32, f64);
+
+impl<F, T> Value for F
+    where F: 'static + Send + Sync + Fn(&RecordInfo) -> T,
+          T: fmt::Display
+{
+    fn serialize(&self, info: &RecordInfo, _key: &str, io: &mut io::Write) -> io::Result<()> {
+        write!(io, "{}", self(info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use Level;
+
+    #[test]
+    fn closure_value_is_invoked_lazily_per_record() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = calls.clone();
+        let value = move |info: &RecordInfo| {
+            calls2.fetch_add(1, Ordering::SeqCst);
+            info.msg().to_owned()
+        };
+
+        let info = RecordInfo::new(Level::Info, "first");
+        let mut buf = Vec::new();
+        let _ = value.serialize(&info, "key", &mut buf);
+        assert_eq!(buf, b"first");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Same closure, a different record: it must be called again and
+        // reflect the new `RecordInfo`, not some value cached from before.
+        let info = RecordInfo::new(Level::Info, "second");
+        buf.clear();
+        let _ = value.serialize(&info, "key", &mut buf);
+        assert_eq!(buf, b"second");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}